@@ -1,28 +1,132 @@
 //! Link is used for linking objects together in a undetermistic way.
 //! It allows the linked objects to last as long as a connection exists.
 //!
-//! However it is your duty to explicitly disconnect the links.
+//! `Link::connect` keeps both sides alive with a strong `Rc`, so it is your
+//! duty to explicitly disconnect the links to avoid a reference cycle.
+//! `Link::connect_weak` avoids that duty on one side by holding only a `Weak`
+//! reference to the target; a `Link` also tears itself down automatically
+//! when it is dropped.
+//!
+//! `Link::reconnect` lets you swap a link's far side without losing track of
+//! what it used to point at, and `Link::set_on_connect`/`set_on_disconnect`
+//! let an owner react the moment its link is established or torn down by the
+//! *other* side, instead of having to poll `connected()`.
 
 #[macro_use]
 extern crate field_offset as offset;
 
-use std::ptr;
+use std::ops::{Deref, DerefMut};
+use std::{mem, ptr};
 use std::{rc, cell};
 
+mod link_list;
+pub use link_list::{LinkList, LinkNode, Cursor, CursorMut};
+
+mod sync_link;
+pub use sync_link::{SyncLink, RemoteGuard};
+
+/// The handle a `Link` keeps on its target: either owning (strong) or
+/// non-owning (weak). See `Link::connect` and `Link::connect_weak`.
+enum TargetHandle<TargetT> {
+    Strong(rc::Rc<cell::RefCell<TargetT>>),
+    Weak(rc::Weak<cell::RefCell<TargetT>>),
+}
+
+impl<TargetT> TargetHandle<TargetT> {
+    fn upgrade(&self) -> Option<rc::Rc<cell::RefCell<TargetT>>> {
+        match *self {
+            TargetHandle::Strong(ref target_obj) => Some(target_obj.clone()),
+            TargetHandle::Weak(ref target_obj) => target_obj.upgrade(),
+        }
+    }
+}
+
 struct LinkData<LocalT, TargetT> {
     offset: offset::FieldOffset<LocalT, Link<LocalT, TargetT>>,
-    target_obj: rc::Rc<cell::RefCell<TargetT>>,
+    target_obj: TargetHandle<TargetT>,
     target_offset: offset::FieldOffset<TargetT, Link<TargetT, LocalT>>,
 }
 
 /// The Link object that represents a link between two structures.
 pub struct Link<LocalT, TargetT> {
     data: Option<LinkData<LocalT, TargetT>>,
+    on_connect: Option<Box<FnMut()>>,
+    on_disconnect: Option<Box<FnMut()>>,
+}
+
+/// A guard borrowing the remote object of a `Link` immutably.
+///
+/// Holds the `Rc` that owns the remote object alongside the live `Ref`
+/// returned by its `RefCell`, so the remote object is kept alive and its
+/// runtime borrow flag stays held for as long as the guard exists. Dereference
+/// it to access the target.
+pub struct RemoteRef<TargetT: 'static> {
+    // `guard` borrows from `owner` below; declared first so it is dropped
+    // (releasing the `RefCell` borrow) before `owner`'s `Rc` is released.
+    guard: cell::Ref<'static, TargetT>,
+    #[allow(dead_code)]
+    owner: rc::Rc<cell::RefCell<TargetT>>,
+}
+
+impl<TargetT: 'static> RemoteRef<TargetT> {
+    fn new(owner: rc::Rc<cell::RefCell<TargetT>>) -> Self {
+        let guard = owner.borrow();
+        // Safety: `guard` is never exposed with a lifetime outside this
+        // struct, and `owner` is dropped after `guard` (field order above),
+        // so the borrow remains valid for as long as `RemoteRef` is alive.
+        let guard = unsafe { mem::transmute::<cell::Ref<TargetT>, cell::Ref<'static, TargetT>>(guard) };
+        RemoteRef { guard, owner }
+    }
+}
+
+impl<TargetT: 'static> Deref for RemoteRef<TargetT> {
+    type Target = TargetT;
+    fn deref(&self) -> &TargetT {
+        &self.guard
+    }
+}
+
+/// A guard borrowing the remote object of a `Link` mutably.
+///
+/// Works like [`RemoteRef`], but wraps a `RefMut` and allows mutable access
+/// to the target through `DerefMut`.
+pub struct RemoteRefMut<TargetT: 'static> {
+    guard: cell::RefMut<'static, TargetT>,
+    #[allow(dead_code)]
+    owner: rc::Rc<cell::RefCell<TargetT>>,
+}
+
+impl<TargetT: 'static> RemoteRefMut<TargetT> {
+    fn new(owner: rc::Rc<cell::RefCell<TargetT>>) -> Self {
+        let guard = owner.borrow_mut();
+        // Safety: see `RemoteRef::new`.
+        let guard = unsafe {
+            mem::transmute::<cell::RefMut<TargetT>, cell::RefMut<'static, TargetT>>(guard)
+        };
+        RemoteRefMut { guard, owner }
+    }
+}
+
+impl<TargetT: 'static> Deref for RemoteRefMut<TargetT> {
+    type Target = TargetT;
+    fn deref(&self) -> &TargetT {
+        &self.guard
+    }
+}
+
+impl<TargetT: 'static> DerefMut for RemoteRefMut<TargetT> {
+    fn deref_mut(&mut self) -> &mut TargetT {
+        &mut self.guard
+    }
 }
 
 impl<LocalT, TargetT> Default for Link<LocalT, TargetT> {
     fn default() -> Self {
-        Link { data: None }
+        Link {
+            data: None,
+            on_connect: None,
+            on_disconnect: None,
+        }
     }
 }
 
@@ -76,17 +180,97 @@ impl<LocalT, TargetT> Link<LocalT, TargetT> {
             second_link_ptr.as_mut().unwrap().disconnect();
             first_link_ptr.as_mut().unwrap().data = Some(LinkData {
                 offset: first_offset,
-                target_obj: second_obj.clone(),
+                target_obj: TargetHandle::Strong(second_obj.clone()),
                 target_offset: second_offset,
             });
             second_link_ptr.as_mut().unwrap().data = Some(LinkData {
                 offset: second_offset,
-                target_obj: first_obj.clone(),
+                target_obj: TargetHandle::Strong(first_obj.clone()),
                 target_offset: first_offset,
-            })
+            });
+            if let Some(ref mut cb) = first_link_ptr.as_mut().unwrap().on_connect {
+                cb();
+            }
+            if let Some(ref mut cb) = second_link_ptr.as_mut().unwrap().on_connect {
+                cb();
+            }
         }
     }
 
+    /// Connect two `Link` objects like `connect`, but `first_obj` only holds a
+    /// weak reference to `second_obj` while `second_obj` holds a strong one.
+    ///
+    /// This breaks the reference cycle that `connect` forms: `second_obj` is
+    /// free to be dropped (and `first_obj`'s link will simply stop resolving
+    /// to anything, see `Link::remote_owner`), while `first_obj` stays alive
+    /// for as long as `second_obj` keeps it linked.
+    pub fn connect_weak(first_obj: &mut rc::Rc<cell::RefCell<LocalT>>,
+                         first_offset: offset::FieldOffset<LocalT, Link<LocalT, TargetT>>,
+                         second_obj: &mut rc::Rc<cell::RefCell<TargetT>>,
+                         second_offset: offset::FieldOffset<TargetT, Link<TargetT, LocalT>>) {
+        let first_link_ptr =
+            first_offset.apply_mut(&mut *first_obj.borrow_mut()) as *mut Link<LocalT, TargetT>;
+        let second_link_ptr =
+            second_offset.apply_mut(&mut *second_obj.borrow_mut()) as *mut Link<TargetT, LocalT>;
+        assert!{first_link_ptr as usize != second_link_ptr as usize};
+        unsafe {
+            first_link_ptr.as_mut().unwrap().disconnect();
+            second_link_ptr.as_mut().unwrap().disconnect();
+            first_link_ptr.as_mut().unwrap().data = Some(LinkData {
+                offset: first_offset,
+                target_obj: TargetHandle::Weak(rc::Rc::downgrade(second_obj)),
+                target_offset: second_offset,
+            });
+            second_link_ptr.as_mut().unwrap().data = Some(LinkData {
+                offset: second_offset,
+                target_obj: TargetHandle::Strong(first_obj.clone()),
+                target_offset: first_offset,
+            });
+            if let Some(ref mut cb) = first_link_ptr.as_mut().unwrap().on_connect {
+                cb();
+            }
+            if let Some(ref mut cb) = second_link_ptr.as_mut().unwrap().on_connect {
+                cb();
+            }
+        }
+    }
+
+    /// Swap this link's far side to `second_obj`, disconnecting the previous
+    /// one (if any) the same way `connect` would, and return the previous
+    /// remote owner.
+    ///
+    /// Unlike calling `disconnect` followed by `connect`, the previous remote
+    /// object is still reachable through the returned guard even though this
+    /// link no longer points at it.
+    pub fn reconnect(first_obj: &mut rc::Rc<cell::RefCell<LocalT>>,
+                      first_offset: offset::FieldOffset<LocalT, Link<LocalT, TargetT>>,
+                      second_obj: &mut rc::Rc<cell::RefCell<TargetT>>,
+                      second_offset: offset::FieldOffset<TargetT, Link<TargetT, LocalT>>)
+                      -> Option<RemoteRef<TargetT>> {
+        let previous = first_offset.apply(&*first_obj.borrow())
+            .data
+            .as_ref()
+            .and_then(|data| data.target_obj.upgrade());
+        Link::connect(first_obj, first_offset, second_obj, second_offset);
+        previous.map(RemoteRef::new)
+    }
+
+    /// Register a callback invoked every time this link becomes connected,
+    /// whether by this side or the other side calling `connect`,
+    /// `connect_weak` or `reconnect`. Replaces any previously registered
+    /// callback.
+    pub fn set_on_connect<F: FnMut() + 'static>(&mut self, callback: F) {
+        self.on_connect = Some(Box::new(callback));
+    }
+
+    /// Register a callback invoked every time this link is torn down,
+    /// whether by this side's own `disconnect` or because the other side
+    /// disconnected, reconnected or was dropped. Replaces any previously
+    /// registered callback.
+    pub fn set_on_disconnect<F: FnMut() + 'static>(&mut self, callback: F) {
+        self.on_disconnect = Some(Box::new(callback));
+    }
+
     /// Create a unconnected `Link' object.`
     pub fn new() -> Self {
         Self::default()
@@ -124,43 +308,72 @@ impl<LocalT, TargetT> Link<LocalT, TargetT> {
         self.owner_mut().map_or(ptr::null_mut(), |r| r as *mut _)
     }
 
-    /// Borrow the object on the other side.
-    pub fn remote_owner(&self) -> Option<&TargetT> {
-        if let Some(data) = self.data.as_ref() {
-            unsafe { Some((&*(*data.target_obj).borrow() as *const _).as_ref().unwrap()) }
-        } else {
-            None
-        }
+    /// Borrow the object on the other side, if connected and (for a weak
+    /// link) still alive.
+    ///
+    /// The returned [`RemoteRef`] keeps the target's `Rc` alive and holds its
+    /// `RefCell` borrow for as long as the guard is in scope, so `RefCell`'s
+    /// runtime borrow checking protects the caller.
+    pub fn remote_owner(&self) -> Option<RemoteRef<TargetT>> {
+        self.data.as_ref().and_then(|data| data.target_obj.upgrade()).map(RemoteRef::new)
     }
 
-    /// Borrow the object on the other side mutably.
-    pub fn remote_owner_mut(&mut self) -> Option<&mut TargetT> {
-        if let Some(data) = self.data.as_ref() {
-            unsafe { Some((&mut *(*data.target_obj).borrow_mut() as *mut _).as_mut().unwrap()) }
-        } else {
-            None
-        }
+    /// Borrow the object on the other side mutably, if connected and (for a
+    /// weak link) still alive.
+    ///
+    /// See [`Link::remote_owner`] for the guard's semantics.
+    pub fn remote_owner_mut(&mut self) -> Option<RemoteRefMut<TargetT>> {
+        self.data.as_ref().and_then(|data| data.target_obj.upgrade()).map(RemoteRefMut::new)
     }
 
+    /// Raw pointer to the object on the other side, bypassing `RefCell`'s
+    /// borrow checking. Prefer [`Link::remote_owner`] unless you need the
+    /// unchecked pointer.
     pub fn remote_owner_ptr(&self) -> *const TargetT {
-        self.remote_owner().map_or(ptr::null(), |r| r as *const _)
+        match self.data.as_ref().and_then(|data| data.target_obj.upgrade()) {
+            Some(target_obj) => unsafe { &*target_obj.borrow() as *const _ },
+            None => ptr::null(),
+        }
     }
 
+    /// Raw pointer to the object on the other side, bypassing `RefCell`'s
+    /// borrow checking. Prefer [`Link::remote_owner_mut`] unless you need the
+    /// unchecked pointer.
     pub fn remote_owner_mut_ptr(&mut self) -> *mut TargetT {
-        self.remote_owner_mut().map_or(ptr::null_mut(), |r| r as *mut _)
+        match self.data.as_ref().and_then(|data| data.target_obj.upgrade()) {
+            Some(target_obj) => unsafe { &mut *target_obj.borrow_mut() as *mut _ },
+            None => ptr::null_mut(),
+        }
     }
 
 
     /// Disconnect the `Link' object if it is connected.
     pub fn disconnect(&mut self) {
         if let Some(data) = self.data.as_mut() {
-            let mut target_mut = data.target_obj
-                .borrow_mut();
-            let target_link = data.target_offset
-                .apply_mut(&mut *target_mut);
-            target_link.data = None;
+            if let Some(target_obj) = data.target_obj.upgrade() {
+                let mut target_mut = target_obj.borrow_mut();
+                let target_link = data.target_offset.apply_mut(&mut *target_mut);
+                if target_link.data.take().is_some() {
+                    if let Some(ref mut cb) = target_link.on_disconnect {
+                        cb();
+                    }
+                }
+            }
         }
-        self.data = None;
+        if self.data.take().is_some() {
+            if let Some(ref mut cb) = self.on_disconnect {
+                cb();
+            }
+        }
+    }
+}
+
+impl<LocalT, TargetT> Drop for Link<LocalT, TargetT> {
+    /// Tears down the reverse side of the link, the same as `disconnect`,
+    /// so a linked object that goes out of scope never leaves the other
+    /// side's `Link` pointing at it.
+    fn drop(&mut self) {
+        self.disconnect();
     }
 }
 
@@ -168,7 +381,7 @@ impl<LocalT, TargetT> Link<LocalT, TargetT> {
 #[cfg(test)]
 mod tests {
     use std::rc::Rc;
-    use std::cell::RefCell;
+    use std::cell::{Cell, RefCell};
     use Link;
 
     #[test]
@@ -219,4 +432,77 @@ mod tests {
         }
         assert!(!a.borrow().link.connected());
     }
+
+    #[test]
+    fn test_weak_no_cycle() {
+        struct A {
+            pub link: Link<A, B>,
+        }
+        struct B {
+            pub data: u32,
+            pub link: Link<B, A>,
+        }
+
+        let mut a = Rc::new(RefCell::new(A { link: Link::new() }));
+        let mut b = Rc::new(RefCell::new(B {
+            data: 7,
+            link: Link::new(),
+        }));
+        let b_weak = Rc::downgrade(&b);
+
+        Link::connect_weak(&mut a, offset_of!{A => link}, &mut b, offset_of!{B => link});
+        assert!(a.borrow().link.connected());
+        assert!(b.borrow().link.connected());
+        assert_eq!(a.borrow().link.remote_owner().unwrap().data, 7);
+
+        // `a` only holds a `Weak` to `b`, so dropping the sole strong `Rc` to
+        // `b` frees it without anyone having called `disconnect`.
+        drop(b);
+        assert!(b_weak.upgrade().is_none());
+        assert!(a.borrow().link.remote_owner().is_none());
+    }
+
+    #[test]
+    fn test_reconnect_and_hooks() {
+        struct A {
+            pub link: Link<A, B>,
+        }
+        struct B {
+            pub data: u32,
+            pub link: Link<B, A>,
+        }
+
+        let mut a = Rc::new(RefCell::new(A { link: Link::new() }));
+        let mut b1 = Rc::new(RefCell::new(B {
+            data: 1,
+            link: Link::new(),
+        }));
+        let mut b2 = Rc::new(RefCell::new(B {
+            data: 2,
+            link: Link::new(),
+        }));
+
+        let connects = Rc::new(Cell::new(0));
+        let disconnects = Rc::new(Cell::new(0));
+        {
+            let connects = connects.clone();
+            let disconnects = disconnects.clone();
+            a.borrow_mut().link.set_on_connect(move || connects.set(connects.get() + 1));
+            a.borrow_mut().link.set_on_disconnect(move || disconnects.set(disconnects.get() + 1));
+        }
+
+        Link::connect(&mut a, offset_of!{A => link}, &mut b1, offset_of!{B => link});
+        assert_eq!(connects.get(), 1);
+
+        let previous = Link::reconnect(&mut a, offset_of!{A => link}, &mut b2, offset_of!{B => link});
+        assert_eq!(previous.unwrap().data, 1);
+        assert_eq!(connects.get(), 2);
+        assert_eq!(disconnects.get(), 1);
+        assert_eq!(a.borrow().link.remote_owner().unwrap().data, 2);
+
+        // `b2` disconnecting its own side should still fire `a`'s hook.
+        b2.borrow_mut().link.disconnect();
+        assert_eq!(disconnects.get(), 2);
+        assert!(!a.borrow().link.connected());
+    }
 }
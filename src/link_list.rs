@@ -0,0 +1,449 @@
+//! An intrusive, doubly-linked, one-to-many counterpart to `Link`.
+//!
+//! A `LinkList<LocalT, TargetT>` lives on a "hub" object (`LocalT`) and owns
+//! an intrusive doubly-linked list of `LinkNode<TargetT, LocalT>`s, each
+//! embedded in one of potentially many "member" objects (`TargetT`), located
+//! via `field_offset` exactly like `Link` locates its data.
+//!
+//! The hub holds a strong `Rc` to each linked member, but each member only
+//! holds a `Weak` back to its hub, the same asymmetric ownership
+//! `Link::connect_weak` uses to avoid a reference cycle. The same asymmetry
+//! is used between neighbouring members themselves: a node's `next` is a
+//! strong `Rc` and its `prev` is only a `Weak`, so a run of 2+ linked members
+//! doesn't keep each other alive in a cycle either. This means a hub with no
+//! other strong references can be dropped without first `unlink()`ing every
+//! member by hand.
+
+use std::marker::PhantomData;
+
+use offset::FieldOffset;
+use {rc, cell};
+use {RemoteRef, RemoteRefMut};
+
+struct LinkNodeData<LocalT, TargetT> {
+    self_offset: FieldOffset<LocalT, LinkNode<LocalT, TargetT>>,
+    // Weak, not `Rc`: the hub's `LinkList` holds a strong ref forward to every
+    // member (see `LinkList`'s `head`/`tail`), so a strong ref back here would
+    // form the same kind of reference cycle `Link::connect_weak` exists to
+    // avoid for the 1:1 case. The hub is reached by `upgrade`ing, and is
+    // simply treated as gone once it has been dropped.
+    hub: rc::Weak<cell::RefCell<TargetT>>,
+    hub_list_offset: FieldOffset<TargetT, LinkList<TargetT, LocalT>>,
+    // Weak, not `Rc`, for the same reason `hub` is: `next` already holds a
+    // strong ref forward to this member from its predecessor, so a strong
+    // ref back here would form a reference cycle between every adjacent
+    // pair of members. Upgraded on use and treated as absent once gone,
+    // which `unlink()` never lets happen to a live neighbour.
+    prev: Option<rc::Weak<cell::RefCell<LocalT>>>,
+    next: Option<rc::Rc<cell::RefCell<LocalT>>>,
+}
+
+/// A single member's slot in a `LinkList`, embedded in the member's struct.
+pub struct LinkNode<LocalT, TargetT> {
+    data: Option<LinkNodeData<LocalT, TargetT>>,
+}
+
+impl<LocalT, TargetT> Default for LinkNode<LocalT, TargetT> {
+    fn default() -> Self {
+        LinkNode { data: None }
+    }
+}
+
+impl<LocalT, TargetT> LinkNode<LocalT, TargetT> {
+    /// Create an unlinked `LinkNode`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Check if this node is currently part of a list.
+    pub fn linked(&self) -> bool {
+        self.data.is_some()
+    }
+
+    /// Borrow the hub that owns the list this node is linked into, if linked
+    /// and the hub is still alive.
+    pub fn hub(&self) -> Option<RemoteRef<TargetT>> {
+        self.data.as_ref().and_then(|data| data.hub.upgrade()).map(RemoteRef::new)
+    }
+
+    /// Borrow the hub that owns the list this node is linked into, mutably,
+    /// if linked and the hub is still alive.
+    pub fn hub_mut(&mut self) -> Option<RemoteRefMut<TargetT>> {
+        self.data.as_ref().and_then(|data| data.hub.upgrade()).map(RemoteRefMut::new)
+    }
+
+    /// Remove this node from its list in O(1), splicing its neighbours
+    /// together and fixing up the hub's head/tail if needed. Safe to call on
+    /// a node that is already unlinked.
+    pub fn unlink(&mut self) {
+        let data = match self.data.take() {
+            Some(data) => data,
+            None => return,
+        };
+
+        let prev_rc = data.prev.as_ref().and_then(rc::Weak::upgrade);
+
+        if let Some(ref prev_rc) = prev_rc {
+            let mut prev_mut = prev_rc.borrow_mut();
+            if let Some(ref mut prev_data) = data.self_offset.apply_mut(&mut *prev_mut).data {
+                prev_data.next = data.next.clone();
+            }
+        }
+        if let Some(ref next_rc) = data.next {
+            let mut next_mut = next_rc.borrow_mut();
+            if let Some(ref mut next_data) = data.self_offset.apply_mut(&mut *next_mut).data {
+                next_data.prev = prev_rc.as_ref().map(rc::Rc::downgrade);
+            }
+        }
+
+        // The hub may already be gone (e.g. it is being dropped right now,
+        // which is what is dropping this node in the first place), in which
+        // case there is no list left to fix up.
+        if let Some(hub_rc) = data.hub.upgrade() {
+            let mut hub_mut = hub_rc.borrow_mut();
+            let list = data.hub_list_offset.apply_mut(&mut *hub_mut);
+            if prev_rc.is_none() {
+                list.head = data.next.clone();
+            }
+            if data.next.is_none() {
+                list.tail = prev_rc.clone();
+            }
+            list.len -= 1;
+        }
+    }
+}
+
+impl<LocalT, TargetT> Drop for LinkNode<LocalT, TargetT> {
+    /// Self-removes from the hub's list, the same as `unlink`, so the hub's
+    /// list never keeps a dangling entry for a dropped member.
+    fn drop(&mut self) {
+        self.unlink();
+    }
+}
+
+/// An intrusive, ordered list of members owned by a hub object.
+///
+/// `LocalT` is the hub's own type and `TargetT` is the member type; members
+/// embed a `LinkNode<TargetT, LocalT>` located via `field_offset`, the same
+/// convention `Link` uses for its two sides.
+pub struct LinkList<LocalT, TargetT> {
+    // `LocalT` (the hub's own type) doesn't appear in any field: it's only
+    // used to type-check the `FieldOffset`s passed into `push_back`/
+    // `push_front`/`cursor`/`cursor_mut`, and to pair with the matching
+    // `LinkNodeData::hub_list_offset` on the member side.
+    _local: PhantomData<LocalT>,
+    head: Option<rc::Rc<cell::RefCell<TargetT>>>,
+    tail: Option<rc::Rc<cell::RefCell<TargetT>>>,
+    len: usize,
+}
+
+impl<LocalT, TargetT> Default for LinkList<LocalT, TargetT> {
+    fn default() -> Self {
+        LinkList {
+            _local: PhantomData,
+            head: None,
+            tail: None,
+            len: 0,
+        }
+    }
+}
+
+impl<LocalT, TargetT> LinkList<LocalT, TargetT> {
+    /// Create an empty `LinkList`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of members currently linked into this list.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Check if the list has no members linked into it.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Link `member_obj` onto the back of `hub_obj`'s list. If the member was
+    /// already linked elsewhere, it is unlinked first.
+    pub fn push_back(hub_obj: &mut rc::Rc<cell::RefCell<LocalT>>,
+                      hub_offset: FieldOffset<LocalT, LinkList<LocalT, TargetT>>,
+                      member_obj: &mut rc::Rc<cell::RefCell<TargetT>>,
+                      member_offset: FieldOffset<TargetT, LinkNode<TargetT, LocalT>>) {
+        let member_node_ptr = member_offset.apply_mut(&mut *member_obj.borrow_mut()) as
+                              *mut LinkNode<TargetT, LocalT>;
+        let list_ptr =
+            hub_offset.apply_mut(&mut *hub_obj.borrow_mut()) as *mut LinkList<LocalT, TargetT>;
+        unsafe {
+            member_node_ptr.as_mut().unwrap().unlink();
+
+            let list = list_ptr.as_mut().unwrap();
+            let old_tail = list.tail.take();
+            list.tail = Some(member_obj.clone());
+            if list.head.is_none() {
+                list.head = Some(member_obj.clone());
+            }
+            list.len += 1;
+
+            member_node_ptr.as_mut().unwrap().data = Some(LinkNodeData {
+                self_offset: member_offset,
+                hub: rc::Rc::downgrade(hub_obj),
+                hub_list_offset: hub_offset,
+                prev: old_tail.as_ref().map(rc::Rc::downgrade),
+                next: None,
+            });
+
+            if let Some(old_tail_rc) = old_tail {
+                let mut old_tail_mut = old_tail_rc.borrow_mut();
+                if let Some(ref mut data) = member_offset.apply_mut(&mut *old_tail_mut).data {
+                    data.next = Some(member_obj.clone());
+                }
+            }
+        }
+    }
+
+    /// Link `member_obj` onto the front of `hub_obj`'s list. If the member was
+    /// already linked elsewhere, it is unlinked first.
+    pub fn push_front(hub_obj: &mut rc::Rc<cell::RefCell<LocalT>>,
+                       hub_offset: FieldOffset<LocalT, LinkList<LocalT, TargetT>>,
+                       member_obj: &mut rc::Rc<cell::RefCell<TargetT>>,
+                       member_offset: FieldOffset<TargetT, LinkNode<TargetT, LocalT>>) {
+        let member_node_ptr = member_offset.apply_mut(&mut *member_obj.borrow_mut()) as
+                              *mut LinkNode<TargetT, LocalT>;
+        let list_ptr =
+            hub_offset.apply_mut(&mut *hub_obj.borrow_mut()) as *mut LinkList<LocalT, TargetT>;
+        unsafe {
+            member_node_ptr.as_mut().unwrap().unlink();
+
+            let list = list_ptr.as_mut().unwrap();
+            let old_head = list.head.take();
+            list.head = Some(member_obj.clone());
+            if list.tail.is_none() {
+                list.tail = Some(member_obj.clone());
+            }
+            list.len += 1;
+
+            member_node_ptr.as_mut().unwrap().data = Some(LinkNodeData {
+                self_offset: member_offset,
+                hub: rc::Rc::downgrade(hub_obj),
+                hub_list_offset: hub_offset,
+                prev: None,
+                next: old_head.clone(),
+            });
+
+            if let Some(old_head_rc) = old_head {
+                let mut old_head_mut = old_head_rc.borrow_mut();
+                if let Some(ref mut data) = member_offset.apply_mut(&mut *old_head_mut).data {
+                    data.prev = Some(rc::Rc::downgrade(member_obj));
+                }
+            }
+        }
+    }
+
+    /// A cursor positioned on the first member, walking the list in order.
+    pub fn cursor(&self,
+                  member_offset: FieldOffset<TargetT, LinkNode<TargetT, LocalT>>)
+                  -> Cursor<LocalT, TargetT> {
+        Cursor {
+            current: self.head.clone(),
+            member_offset: member_offset,
+        }
+    }
+
+    /// A cursor positioned on the first member, walking the list in order,
+    /// yielding mutable access to each member.
+    pub fn cursor_mut(&self,
+                       member_offset: FieldOffset<TargetT, LinkNode<TargetT, LocalT>>)
+                       -> CursorMut<LocalT, TargetT> {
+        CursorMut {
+            current: self.head.clone(),
+            member_offset: member_offset,
+        }
+    }
+}
+
+/// Walks a `LinkList` in order, borrowing each member immutably.
+pub struct Cursor<LocalT, TargetT> {
+    current: Option<rc::Rc<cell::RefCell<TargetT>>>,
+    member_offset: FieldOffset<TargetT, LinkNode<TargetT, LocalT>>,
+}
+
+impl<LocalT, TargetT> Cursor<LocalT, TargetT> {
+    /// Borrow the member the cursor is currently positioned on, if any.
+    pub fn current(&self) -> Option<RemoteRef<TargetT>> {
+        self.current.as_ref().map(|rc| RemoteRef::new(rc.clone()))
+    }
+
+    /// Advance the cursor to the next member.
+    pub fn move_next(&mut self) {
+        self.current = self.current.as_ref().and_then(|rc| {
+            let member = rc.borrow();
+            self.member_offset.apply(&*member).data.as_ref().and_then(|data| data.next.clone())
+        });
+    }
+
+    /// Move the cursor to the previous member.
+    pub fn move_prev(&mut self) {
+        self.current = self.current.as_ref().and_then(|rc| {
+            let member = rc.borrow();
+            self.member_offset
+                .apply(&*member)
+                .data
+                .as_ref()
+                .and_then(|data| data.prev.as_ref().and_then(rc::Weak::upgrade))
+        });
+    }
+}
+
+/// Walks a `LinkList` in order, borrowing each member mutably.
+pub struct CursorMut<LocalT, TargetT> {
+    current: Option<rc::Rc<cell::RefCell<TargetT>>>,
+    member_offset: FieldOffset<TargetT, LinkNode<TargetT, LocalT>>,
+}
+
+impl<LocalT, TargetT> CursorMut<LocalT, TargetT> {
+    /// Borrow the member the cursor is currently positioned on mutably, if any.
+    pub fn current(&self) -> Option<RemoteRefMut<TargetT>> {
+        self.current.as_ref().map(|rc| RemoteRefMut::new(rc.clone()))
+    }
+
+    /// Advance the cursor to the next member.
+    pub fn move_next(&mut self) {
+        self.current = self.current.as_ref().and_then(|rc| {
+            let member = rc.borrow();
+            self.member_offset.apply(&*member).data.as_ref().and_then(|data| data.next.clone())
+        });
+    }
+
+    /// Move the cursor to the previous member.
+    pub fn move_prev(&mut self) {
+        self.current = self.current.as_ref().and_then(|rc| {
+            let member = rc.borrow();
+            self.member_offset
+                .apply(&*member)
+                .data
+                .as_ref()
+                .and_then(|data| data.prev.as_ref().and_then(rc::Weak::upgrade))
+        });
+    }
+
+    /// Unlink the current member from the list and advance the cursor to
+    /// what was its next member.
+    pub fn remove_current(&mut self) {
+        if let Some(rc) = self.current.take() {
+            let next = {
+                let mut member_mut = rc.borrow_mut();
+                let node = self.member_offset.apply_mut(&mut *member_mut);
+                let next = node.data.as_ref().and_then(|data| data.next.clone());
+                node.unlink();
+                next
+            };
+            self.current = next;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+    use std::cell::RefCell;
+    use {LinkList, LinkNode};
+
+    struct Hub {
+        pub members: LinkList<Hub, Member>,
+    }
+    struct Member {
+        pub data: u32,
+        pub node: LinkNode<Member, Hub>,
+    }
+
+    #[test]
+    fn test_push_and_walk() {
+        let mut hub = Rc::new(RefCell::new(Hub { members: LinkList::new() }));
+        let mut m1 = Rc::new(RefCell::new(Member {
+            data: 1,
+            node: LinkNode::new(),
+        }));
+        let mut m2 = Rc::new(RefCell::new(Member {
+            data: 2,
+            node: LinkNode::new(),
+        }));
+
+        LinkList::push_back(&mut hub, offset_of!{Hub => members}, &mut m1, offset_of!{Member => node});
+        LinkList::push_back(&mut hub, offset_of!{Hub => members}, &mut m2, offset_of!{Member => node});
+        assert_eq!(hub.borrow().members.len(), 2);
+
+        let mut seen = Vec::new();
+        let mut cursor = hub.borrow().members.cursor(offset_of!{Member => node});
+        while let Some(member) = cursor.current() {
+            seen.push(member.data);
+            cursor.move_next();
+        }
+        assert_eq!(seen, vec![1, 2]);
+
+        m1.borrow_mut().node.unlink();
+        assert_eq!(hub.borrow().members.len(), 1);
+        assert!(!m1.borrow().node.linked());
+
+        m2.borrow_mut().node.unlink();
+        assert_eq!(hub.borrow().members.len(), 0);
+        assert!(!m2.borrow().node.linked());
+    }
+
+    #[test]
+    fn test_drop_hub_no_cycle() {
+        let mut hub = Rc::new(RefCell::new(Hub { members: LinkList::new() }));
+        let hub_weak = Rc::downgrade(&hub);
+        let mut m1 = Rc::new(RefCell::new(Member {
+            data: 1,
+            node: LinkNode::new(),
+        }));
+        let m1_weak = Rc::downgrade(&m1);
+
+        LinkList::push_back(&mut hub, offset_of!{Hub => members}, &mut m1, offset_of!{Member => node});
+        drop(m1);
+
+        // Nothing but `hub`'s list and `hub` itself keeps either alive, so
+        // dropping `hub` must free both without anyone calling `unlink()`.
+        drop(hub);
+        assert!(hub_weak.upgrade().is_none());
+        assert!(m1_weak.upgrade().is_none());
+    }
+
+    #[test]
+    fn test_drop_hub_no_cycle_multi_member() {
+        let mut hub = Rc::new(RefCell::new(Hub { members: LinkList::new() }));
+        let hub_weak = Rc::downgrade(&hub);
+        let mut m1 = Rc::new(RefCell::new(Member {
+            data: 1,
+            node: LinkNode::new(),
+        }));
+        let mut m2 = Rc::new(RefCell::new(Member {
+            data: 2,
+            node: LinkNode::new(),
+        }));
+        let mut m3 = Rc::new(RefCell::new(Member {
+            data: 3,
+            node: LinkNode::new(),
+        }));
+        let m1_weak = Rc::downgrade(&m1);
+        let m2_weak = Rc::downgrade(&m2);
+        let m3_weak = Rc::downgrade(&m3);
+
+        LinkList::push_back(&mut hub, offset_of!{Hub => members}, &mut m1, offset_of!{Member => node});
+        LinkList::push_back(&mut hub, offset_of!{Hub => members}, &mut m2, offset_of!{Member => node});
+        LinkList::push_back(&mut hub, offset_of!{Hub => members}, &mut m3, offset_of!{Member => node});
+        drop(m1);
+        drop(m2);
+        drop(m3);
+
+        // A mutual `prev`/`next` reference cycle between adjacent members,
+        // independent of the hub, would leak all three here even after the
+        // hub is dropped.
+        drop(hub);
+        assert!(hub_weak.upgrade().is_none());
+        assert!(m1_weak.upgrade().is_none());
+        assert!(m2_weak.upgrade().is_none());
+        assert!(m3_weak.upgrade().is_none());
+    }
+}
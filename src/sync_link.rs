@@ -0,0 +1,439 @@
+//! A thread-safe counterpart to `Link`, built on `Arc<Mutex<_>>` instead of
+//! `Rc<RefCell<_>>`, so linked object graphs can be shared across threads.
+
+use std::ops::{Deref, DerefMut};
+use std::{mem, ptr, thread};
+use std::sync;
+
+use offset::FieldOffset;
+
+/// Wraps a `FieldOffset` to mark it `Send`/`Sync`.
+///
+/// `FieldOffset` carries a `PhantomData` over a `Fn` trait object purely to
+/// relate `T` and `U`'s lifetimes; it never actually touches a `T` or `U` at
+/// runtime beyond the byte offset computed once at construction, so sharing
+/// one across threads is sound even though the `field_offset` crate doesn't
+/// derive that itself. Stored instead of a bare `FieldOffset` so a linked
+/// `SyncLinkData` remains `Send`/`Sync` like the rest of `SyncLink`.
+struct SyncFieldOffset<T, U>(FieldOffset<T, U>);
+
+impl<T, U> Clone for SyncFieldOffset<T, U> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T, U> Copy for SyncFieldOffset<T, U> {}
+
+unsafe impl<T, U> Send for SyncFieldOffset<T, U> {}
+unsafe impl<T, U> Sync for SyncFieldOffset<T, U> {}
+
+struct SyncLinkData<LocalT, TargetT> {
+    offset: SyncFieldOffset<LocalT, SyncLink<LocalT, TargetT>>,
+    target_obj: sync::Arc<sync::Mutex<TargetT>>,
+    target_offset: SyncFieldOffset<TargetT, SyncLink<TargetT, LocalT>>,
+}
+
+/// The thread-safe counterpart to `Link`, linking two structures held behind
+/// `Arc<Mutex<_>>` instead of `Rc<RefCell<_>>`.
+pub struct SyncLink<LocalT, TargetT> {
+    data: Option<SyncLinkData<LocalT, TargetT>>,
+}
+
+/// A guard borrowing the remote object of a `SyncLink`.
+///
+/// Holds the `Arc` that owns the remote object alongside the live
+/// `MutexGuard`, so the remote object is kept alive and locked for as long as
+/// the guard is in scope. Dereference it (mutably, if needed) to access the
+/// target.
+pub struct RemoteGuard<TargetT: 'static> {
+    // `guard` borrows from `owner` below; declared first so it is dropped
+    // (releasing the lock) before `owner`'s `Arc` is released.
+    guard: sync::MutexGuard<'static, TargetT>,
+    #[allow(dead_code)]
+    owner: sync::Arc<sync::Mutex<TargetT>>,
+}
+
+impl<TargetT: 'static> RemoteGuard<TargetT> {
+    fn new(owner: sync::Arc<sync::Mutex<TargetT>>) -> Self {
+        let guard = owner.lock().unwrap();
+        // Safety: see `link::RemoteRef::new`, the same technique applied to
+        // `MutexGuard` instead of `Ref`.
+        let guard = unsafe {
+            mem::transmute::<sync::MutexGuard<TargetT>, sync::MutexGuard<'static, TargetT>>(guard)
+        };
+        RemoteGuard { guard, owner }
+    }
+}
+
+impl<TargetT: 'static> Deref for RemoteGuard<TargetT> {
+    type Target = TargetT;
+    fn deref(&self) -> &TargetT {
+        &self.guard
+    }
+}
+
+impl<TargetT: 'static> DerefMut for RemoteGuard<TargetT> {
+    fn deref_mut(&mut self) -> &mut TargetT {
+        &mut self.guard
+    }
+}
+
+impl<LocalT, TargetT> Default for SyncLink<LocalT, TargetT> {
+    fn default() -> Self {
+        SyncLink { data: None }
+    }
+}
+
+impl<LocalT, TargetT> SyncLink<LocalT, TargetT> {
+    /// Connect two `SyncLink` objects inside two different mutex-guarded
+    /// structures.
+    ///
+    /// The two mutexes are always locked in the same order regardless of
+    /// which side calls `connect` first or in what order the arguments are
+    /// given: by comparing the addresses of `first_obj` and `second_obj`,
+    /// which the `assert!` below also requires to be distinct. This avoids
+    /// a deadlock if two threads race to connect the same pair of objects in
+    /// opposite order.
+    ///
+    /// If either side was already linked elsewhere, tearing down that old
+    /// link needs a third mutex, one `connect` didn't choose and can't put
+    /// in address order against the two it already locked. Rather than risk
+    /// deadlocking against some other thread working its way towards
+    /// `first_obj`/`second_obj` through that same stale partner (e.g. via
+    /// `disconnect()`, called directly on a lock it already holds), `connect`
+    /// only ever *tries* to lock a stale partner; if that would block, it
+    /// drops both of its own locks and starts over, the same
+    /// drop-and-retry escape hatch `disconnect` can't use, since it never
+    /// owns the lock its caller took on its own object.
+    pub fn connect(first_obj: &mut sync::Arc<sync::Mutex<LocalT>>,
+                    first_offset: FieldOffset<LocalT, SyncLink<LocalT, TargetT>>,
+                    second_obj: &mut sync::Arc<sync::Mutex<TargetT>>,
+                    second_offset: FieldOffset<TargetT, SyncLink<TargetT, LocalT>>) {
+        let first_arc = first_obj.clone();
+        let second_arc = second_obj.clone();
+        let first_addr = sync::Arc::as_ptr(&first_arc) as usize;
+        let second_addr = sync::Arc::as_ptr(&second_arc) as usize;
+        assert!(first_addr != second_addr);
+
+        loop {
+            let (mut first_guard, mut second_guard) = if first_addr < second_addr {
+                let first_guard = first_arc.lock().unwrap();
+                let second_guard = second_arc.lock().unwrap();
+                (first_guard, second_guard)
+            } else {
+                let second_guard = second_arc.lock().unwrap();
+                let first_guard = first_arc.lock().unwrap();
+                (first_guard, second_guard)
+            };
+
+            let linked = Self::link_locked(&mut first_guard,
+                                            first_offset,
+                                            &first_arc,
+                                            &mut second_guard,
+                                            second_offset,
+                                            &second_arc);
+            if linked {
+                return;
+            }
+            // A stale partner couldn't be locked without blocking; `first_guard`
+            // and `second_guard` drop here, releasing both locks, before the
+            // next attempt.
+            drop(first_guard);
+            drop(second_guard);
+            thread::yield_now();
+        }
+    }
+
+    /// Tears down any existing links and relinks `first`/`second` to each
+    /// other, using the already-held `first_guard`/`second_guard`. Returns
+    /// `false` without changing anything if a stale partner's lock isn't
+    /// immediately available, so the caller can drop its own locks and
+    /// retry instead of risking a deadlock.
+    fn link_locked(first_guard: &mut LocalT,
+                    first_offset: FieldOffset<LocalT, SyncLink<LocalT, TargetT>>,
+                    first_obj: &sync::Arc<sync::Mutex<LocalT>>,
+                    second_guard: &mut TargetT,
+                    second_offset: FieldOffset<TargetT, SyncLink<TargetT, LocalT>>,
+                    second_obj: &sync::Arc<sync::Mutex<TargetT>>)
+                    -> bool {
+        // Peek at each side's existing partner, if any, without touching
+        // either link yet: if tearing it down needs a third mutex, it must
+        // be `try_lock`ed (see below) before anything here is mutated, so a
+        // failed attempt can be abandoned cleanly by the caller.
+        let first_old_target = first_offset.apply(first_guard).data.as_ref().map(|d| d.target_obj.clone());
+        let second_old_target = second_offset.apply(second_guard).data.as_ref().map(|d| d.target_obj.clone());
+
+        // A stale partner is only locked here if it isn't `first_obj`/
+        // `second_obj` themselves (those are already held via
+        // `first_guard`/`second_guard`). Using `try_lock` instead of `lock`
+        // means this never blocks while holding two other locks already.
+        let first_old_guard = match first_old_target {
+            Some(ref target) if !sync::Arc::ptr_eq(target, second_obj) => {
+                match target.try_lock() {
+                    Ok(guard) => Some(guard),
+                    Err(_) => return false,
+                }
+            }
+            _ => None,
+        };
+        let second_old_guard = match second_old_target {
+            Some(ref target) if !sync::Arc::ptr_eq(target, first_obj) => {
+                match target.try_lock() {
+                    Ok(guard) => Some(guard),
+                    Err(_) => return false,
+                }
+            }
+            _ => None,
+        };
+
+        // Every lock needed is held now, so the rest can't fail.
+        let first_old = first_offset.apply_mut(first_guard).data.take();
+        let second_old = second_offset.apply_mut(second_guard).data.take();
+
+        if let Some(old) = first_old {
+            if sync::Arc::ptr_eq(&old.target_obj, second_obj) {
+                second_offset.apply_mut(second_guard).data = None;
+            } else {
+                let mut target_guard = first_old_guard.unwrap();
+                old.target_offset.0.apply_mut(&mut *target_guard).data = None;
+            }
+        }
+        if let Some(old) = second_old {
+            if sync::Arc::ptr_eq(&old.target_obj, first_obj) {
+                first_offset.apply_mut(first_guard).data = None;
+            } else {
+                let mut target_guard = second_old_guard.unwrap();
+                old.target_offset.0.apply_mut(&mut *target_guard).data = None;
+            }
+        }
+
+        first_offset.apply_mut(first_guard).data = Some(SyncLinkData {
+            offset: SyncFieldOffset(first_offset),
+            target_obj: second_obj.clone(),
+            target_offset: SyncFieldOffset(second_offset),
+        });
+        second_offset.apply_mut(second_guard).data = Some(SyncLinkData {
+            offset: SyncFieldOffset(second_offset),
+            target_obj: first_obj.clone(),
+            target_offset: SyncFieldOffset(first_offset),
+        });
+
+        true
+    }
+
+    /// Create an unconnected `SyncLink` object.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Check if the `SyncLink` object is connected.
+    pub fn connected(&self) -> bool {
+        self.data.is_some()
+    }
+
+    /// Borrow the object on this side if connected.
+    pub fn owner(&self) -> Option<&LocalT> {
+        if let Some(data) = self.data.as_ref() {
+            unsafe { Some(data.offset.0.unapply(self)) }
+        } else {
+            None
+        }
+    }
+
+    /// Borrow the object on this side mutably if connected.
+    pub fn owner_mut(&mut self) -> Option<&mut LocalT> {
+        let self_mut_ptr = self as *mut _;
+        if let Some(data) = self.data.as_ref() {
+            unsafe { Some(data.offset.0.unapply_mut(self_mut_ptr.as_mut().unwrap())) }
+        } else {
+            None
+        }
+    }
+
+    pub fn owner_ptr(&self) -> *const LocalT {
+        self.owner().map_or(ptr::null(), |r| r as *const _)
+    }
+
+    pub fn owner_mut_ptr(&mut self) -> *mut LocalT {
+        self.owner_mut().map_or(ptr::null_mut(), |r| r as *mut _)
+    }
+
+    /// Borrow the object on the other side.
+    ///
+    /// The returned [`RemoteGuard`] keeps the target's `Arc` alive and holds
+    /// its `Mutex` lock for as long as the guard is in scope.
+    pub fn remote_owner(&self) -> Option<RemoteGuard<TargetT>> {
+        self.data.as_ref().map(|data| RemoteGuard::new(data.target_obj.clone()))
+    }
+
+    /// Borrow the object on the other side mutably.
+    ///
+    /// See [`SyncLink::remote_owner`] for the guard's semantics.
+    pub fn remote_owner_mut(&mut self) -> Option<RemoteGuard<TargetT>> {
+        self.data.as_ref().map(|data| RemoteGuard::new(data.target_obj.clone()))
+    }
+
+    /// Disconnect the `SyncLink` object if it is connected.
+    ///
+    /// Locks the other side's mutex to clear its half of the link. Unlike
+    /// `connect`, this can't drop-and-retry its way out of a lock ordering
+    /// conflict: its own object's mutex was already locked by the caller,
+    /// outside this method's control, before `disconnect` was ever called.
+    /// Callers that hold a lock obtained directly (not through `connect`)
+    /// must therefore be careful not to call `disconnect` while some other
+    /// thread could be locking these same two objects in the opposite
+    /// order.
+    pub fn disconnect(&mut self) {
+        if let Some(data) = self.data.as_mut() {
+            let mut target_guard = data.target_obj.lock().unwrap();
+            let target_link = data.target_offset.0.apply_mut(&mut *target_guard);
+            target_link.data = None;
+        }
+        self.data = None;
+    }
+}
+
+impl<LocalT, TargetT> Drop for SyncLink<LocalT, TargetT> {
+    /// Tears down the reverse side of the link, the same as `disconnect`,
+    /// mirroring `Link`'s automatic teardown on drop.
+    fn drop(&mut self) {
+        self.disconnect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+    use SyncLink;
+
+    #[test]
+    fn test_owned() {
+        struct A {
+            pub data: u32,
+            pub link: SyncLink<A, B>,
+        }
+        struct B {
+            pub data: String,
+            pub link: SyncLink<B, A>,
+        }
+
+        let mut a = Arc::new(Mutex::new(A {
+            data: 42,
+            link: SyncLink::new(),
+        }));
+        let mut b = Arc::new(Mutex::new(B {
+            data: "hello".to_owned(),
+            link: SyncLink::new(),
+        }));
+
+        SyncLink::connect(&mut a, offset_of!{A => link}, &mut b, offset_of!{B => link});
+        assert!(a.lock().unwrap().link.connected());
+        assert!(b.lock().unwrap().link.connected());
+        assert_eq!(a.lock().unwrap().link.remote_owner().unwrap().data,
+                   "hello".to_owned());
+        assert_eq!(b.lock().unwrap().link.remote_owner().unwrap().data, 42);
+
+        a.lock().unwrap().link.disconnect();
+        assert!(!a.lock().unwrap().link.connected());
+        assert!(!b.lock().unwrap().link.connected());
+    }
+
+    #[test]
+    fn test_concurrent_connect_no_deadlock() {
+        struct A {
+            pub link: SyncLink<A, B>,
+        }
+        struct B {
+            pub link: SyncLink<B, A>,
+        }
+
+        let a = Arc::new(Mutex::new(A { link: SyncLink::new() }));
+        let b = Arc::new(Mutex::new(B { link: SyncLink::new() }));
+
+        // Two threads race to connect the same pair in opposite argument
+        // order; `connect`'s pointer-address lock ordering must keep them
+        // from deadlocking on each other's mutex.
+        let (done_tx, done_rx) = mpsc::channel();
+
+        let mut a1 = a.clone();
+        let mut b1 = b.clone();
+        let tx1 = done_tx.clone();
+        let t1 = thread::spawn(move || {
+            // `offset_of!` is computed inside the thread: `FieldOffset`
+            // carries a `PhantomData<dyn Fn(..)>` marker that isn't `Send`,
+            // so a value built outside can't simply be moved across threads.
+            for _ in 0..1000 {
+                SyncLink::connect(&mut a1, offset_of!{A => link}, &mut b1, offset_of!{B => link});
+            }
+            tx1.send(()).unwrap();
+        });
+
+        let mut a2 = a.clone();
+        let mut b2 = b.clone();
+        let tx2 = done_tx;
+        let t2 = thread::spawn(move || {
+            for _ in 0..1000 {
+                SyncLink::connect(&mut b2, offset_of!{B => link}, &mut a2, offset_of!{A => link});
+            }
+            tx2.send(()).unwrap();
+        });
+
+        for _ in 0..2 {
+            done_rx.recv_timeout(Duration::from_secs(5))
+                .expect("threads did not finish within timeout; possible deadlock");
+        }
+        t1.join().unwrap();
+        t2.join().unwrap();
+
+        assert!(a.lock().unwrap().link.connected());
+        assert!(b.lock().unwrap().link.connected());
+    }
+
+    #[test]
+    fn test_reconnect_vs_concurrent_disconnect_no_deadlock() {
+        struct A {
+            pub link: SyncLink<A, B>,
+        }
+        struct B {
+            pub link: SyncLink<B, A>,
+        }
+
+        // One thread reconnects `a` from `b_old` to `b_new` while another
+        // disconnects `b_old` directly, racing `connect`'s stale-partner
+        // teardown (which needs `b_old`'s lock) against `disconnect` (which
+        // needs `a`'s lock) in opposite order.
+        for _ in 0..200 {
+            let mut a = Arc::new(Mutex::new(A { link: SyncLink::new() }));
+            let mut b_old = Arc::new(Mutex::new(B { link: SyncLink::new() }));
+            let mut b_new = Arc::new(Mutex::new(B { link: SyncLink::new() }));
+            SyncLink::connect(&mut a, offset_of!{A => link}, &mut b_old, offset_of!{B => link});
+
+            let (done_tx, done_rx) = mpsc::channel();
+
+            let mut a1 = a.clone();
+            let mut b_new1 = b_new.clone();
+            let tx1 = done_tx.clone();
+            let t1 = thread::spawn(move || {
+                SyncLink::connect(&mut a1, offset_of!{A => link}, &mut b_new1, offset_of!{B => link});
+                tx1.send(()).unwrap();
+            });
+
+            let b_old2 = b_old.clone();
+            let tx2 = done_tx;
+            let t2 = thread::spawn(move || {
+                b_old2.lock().unwrap().link.disconnect();
+                tx2.send(()).unwrap();
+            });
+
+            for _ in 0..2 {
+                done_rx.recv_timeout(Duration::from_secs(5))
+                    .expect("threads did not finish within timeout; possible deadlock");
+            }
+            t1.join().unwrap();
+            t2.join().unwrap();
+        }
+    }
+}